@@ -0,0 +1,299 @@
+// Copyright 2023 The Pigweed Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! # pw_tokenizer
+//!
+//! `pw_tokenizer` re-exports the proc macros provided by `pw_tokenizer_macro`
+//! under their public names and provides the runtime support that the
+//! macros' generated code calls into to serialize a token and its arguments.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub use pw_tokenizer_macro::{
+    _token as token, _token_with_domain as token_with_domain,
+    _tokenize_to_buffer as tokenize_to_buffer, _tokenize_to_writer as tokenize_to_writer,
+};
+
+/// Errors produced while serializing a tokenized message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The destination buffer ran out of room, or a message did not fit in
+    /// the 32-byte scratch buffer it is encoded into before being handed to
+    /// a writer.
+    BufferTooSmall,
+}
+
+// Private API used by `pw_tokenizer_macro`'s generated code. Not part of the
+// public API.
+#[doc(hidden)]
+pub mod internal {
+    use crate::Error;
+
+    /// A single tokenized argument, tagged by the wire encoding the
+    /// detokenizer uses to decode it.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Argument<'a> {
+        String(&'a str),
+        Char(u8),
+        /// A 32-bit integer, zig-zag + LEB128 varint encoded.
+        Varint(i32),
+        /// A 64-bit integer, zig-zag + LEB128 varint encoded.
+        Varint64(i64),
+        /// A 4-byte little-endian IEEE-754 float.
+        Float(f32),
+        /// An 8-byte little-endian IEEE-754 float.
+        Double(f64),
+    }
+
+    impl Argument<'_> {
+        /// Appends this argument's wire encoding to `buf`, returning the
+        /// number of bytes written, or `None` if `buf` is too small.
+        fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+            match self {
+                Self::String(s) => encode_bytes(s.as_bytes(), buf),
+                Self::Char(c) => encode_bytes(&[*c], buf),
+                Self::Varint(value) => encode_varint(zigzag_encode(i64::from(*value)), buf),
+                Self::Varint64(value) => encode_varint(zigzag_encode(*value), buf),
+                Self::Float(value) => encode_bytes(&value.to_le_bytes(), buf),
+                Self::Double(value) => encode_bytes(&value.to_le_bytes(), buf),
+            }
+        }
+    }
+
+    fn encode_bytes(bytes: &[u8], buf: &mut [u8]) -> Option<usize> {
+        let dest = buf.get_mut(..bytes.len())?;
+        dest.copy_from_slice(bytes);
+        Some(bytes.len())
+    }
+
+    // Zig-zag encodes a signed integer into an unsigned one so that
+    // small-magnitude negative values stay small after varint encoding,
+    // matching the encoding used by the C/C++ tokenizer.
+    fn zigzag_encode(value: i64) -> u64 {
+        ((value << 1) ^ (value >> 63)) as u64
+    }
+
+    // LEB128-encodes `value` into `buf`, least-significant group first,
+    // returning the number of bytes written, or `None` if `buf` is too
+    // small.
+    fn encode_varint(mut value: u64, buf: &mut [u8]) -> Option<usize> {
+        let mut len = 0;
+        loop {
+            let mut byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value != 0 {
+                byte |= 0x80;
+            }
+            *buf.get_mut(len)? = byte;
+            len += 1;
+            if value == 0 {
+                return Some(len);
+            }
+        }
+    }
+
+    // Writes `token`'s 4-byte little-endian wire representation into `buf`,
+    // returning the number of bytes written, or `None` if `buf` is too
+    // small.
+    fn encode_token(token: u32, buf: &mut [u8]) -> Option<usize> {
+        encode_bytes(&token.to_le_bytes(), buf)
+    }
+
+    // Encodes `token` followed by each of `args` into `buf`, returning the
+    // total number of bytes written.
+    fn encode_message(token: u32, args: &[Argument], buf: &mut [u8]) -> Result<usize, Error> {
+        let mut len = encode_token(token, buf).ok_or(Error::BufferTooSmall)?;
+        for arg in args {
+            len += arg
+                .encode(buf.get_mut(len..).ok_or(Error::BufferTooSmall)?)
+                .ok_or(Error::BufferTooSmall)?;
+        }
+        Ok(len)
+    }
+
+    /// Encodes `token` into `buffer`. Called by the `tokenize_to_buffer!`
+    /// macro's generated code when the format string has no arguments.
+    pub fn tokenize_to_buffer_no_args<B: AsMut<[u8]>>(
+        mut buffer: B,
+        token: u32,
+    ) -> Result<usize, Error> {
+        encode_token(token, buffer.as_mut()).ok_or(Error::BufferTooSmall)
+    }
+
+    /// Encodes `token` and `args` into `buffer`. Called by the
+    /// `tokenize_to_buffer!` macro's generated code.
+    pub fn tokenize_to_buffer<B: AsMut<[u8]>>(
+        mut buffer: B,
+        token: u32,
+        args: &[Argument],
+    ) -> Result<usize, Error> {
+        encode_message(token, args, buffer.as_mut())
+    }
+
+    /// A destination that a tokenized message can be written to, selected by
+    /// the type passed as the first argument of `tokenize_to_writer!`, which
+    /// the generated code forwards as a turbofish type parameter
+    /// (e.g. `tokenize_to_writer::<MyWriter>(...)`).
+    pub trait TokenizeToWriter {
+        /// Writes `bytes` to this destination.
+        fn write(bytes: &[u8]) -> Result<(), Error>;
+    }
+
+    /// Encodes `token` and writes it via `W`. Called by the
+    /// `tokenize_to_writer!` macro's generated code when the format string
+    /// has no arguments.
+    pub fn tokenize_to_writer_no_args<W: TokenizeToWriter>(token: u32) -> Result<(), Error> {
+        W::write(&token.to_le_bytes())
+    }
+
+    /// Encodes `token` and `args` and writes them via `W`. Called by the
+    /// `tokenize_to_writer!` macro's generated code. The message is encoded
+    /// into a 32-byte scratch buffer first; a longer message returns
+    /// [`Error::BufferTooSmall`] without calling `W::write`.
+    pub fn tokenize_to_writer<W: TokenizeToWriter>(
+        token: u32,
+        args: &[Argument],
+    ) -> Result<(), Error> {
+        let mut buf = [0u8; 32];
+        let len = encode_message(token, args, &mut buf)?;
+        W::write(&buf[..len])
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // Minimal fixed-capacity byte buffer so tests can compare encoded
+        // output without depending on `alloc`.
+        struct Encoded {
+            buf: [u8; 16],
+            len: usize,
+        }
+
+        fn encode(arg: Argument) -> Encoded {
+            let mut buf = [0u8; 16];
+            let len = arg.encode(&mut buf).unwrap();
+            Encoded { buf, len }
+        }
+
+        fn decode_varint(encoded: &Encoded) -> u64 {
+            let mut value = 0u64;
+            let mut shift = 0;
+            for &byte in &encoded.buf[..encoded.len] {
+                value |= u64::from(byte & 0x7f) << shift;
+                shift += 7;
+            }
+            value
+        }
+
+        #[test]
+        fn encodes_small_positive_varint_in_one_byte() {
+            let encoded = encode(Argument::Varint(1));
+            assert_eq!(&encoded.buf[..encoded.len], &[0x02]);
+        }
+
+        #[test]
+        fn encodes_small_negative_varint_in_one_byte() {
+            // Zig-zag maps -1 to 1, which fits in a single LEB128 byte.
+            let encoded = encode(Argument::Varint(-1));
+            assert_eq!(&encoded.buf[..encoded.len], &[0x01]);
+        }
+
+        #[test]
+        fn varint64_round_trips_a_value_that_does_not_fit_in_32_bits() {
+            let value = 1i64 << 40;
+            let encoded = encode(Argument::Varint64(value));
+            assert_eq!(decode_varint(&encoded), zigzag_encode(value));
+        }
+
+        #[test]
+        fn varint64_round_trips_i64_min_without_overflow() {
+            let encoded = encode(Argument::Varint64(i64::MIN));
+            assert_eq!(decode_varint(&encoded), zigzag_encode(i64::MIN));
+        }
+
+        #[test]
+        fn float_is_encoded_as_4_little_endian_bytes() {
+            let encoded = encode(Argument::Float(1.5));
+            assert_eq!(&encoded.buf[..encoded.len], &1.5f32.to_le_bytes());
+        }
+
+        #[test]
+        fn double_is_encoded_as_8_little_endian_bytes() {
+            let encoded = encode(Argument::Double(1.5));
+            assert_eq!(&encoded.buf[..encoded.len], &1.5f64.to_le_bytes());
+        }
+
+        #[test]
+        fn encode_into_too_small_buffer_returns_none() {
+            let mut buf = [0u8; 1];
+            assert_eq!(Argument::Double(1.5).encode(&mut buf), None);
+        }
+
+        struct RecordingWriter;
+
+        impl TokenizeToWriter for RecordingWriter {
+            fn write(bytes: &[u8]) -> Result<(), Error> {
+                WRITTEN.with(|written| written.borrow_mut().extend_from_slice(bytes));
+                Ok(())
+            }
+        }
+
+        std::thread_local! {
+            static WRITTEN: std::cell::RefCell<std::vec::Vec<u8>> = const { std::cell::RefCell::new(std::vec::Vec::new()) };
+        }
+
+        #[test]
+        fn tokenize_to_buffer_writes_token_then_args() {
+            let mut buf = [0u8; 32];
+            let len = tokenize_to_buffer(
+                &mut buf,
+                0x1234_5678,
+                &[Argument::Varint(1), Argument::Char(b'x')],
+            )
+            .unwrap();
+
+            assert_eq!(&buf[..4], &0x1234_5678u32.to_le_bytes());
+            assert_eq!(&buf[4..len], &[0x02, b'x']);
+        }
+
+        #[test]
+        fn tokenize_to_buffer_no_args_writes_only_the_token() {
+            let mut buf = [0u8; 4];
+            let len = tokenize_to_buffer_no_args(&mut buf, 0xdead_beef).unwrap();
+            assert_eq!(&buf[..len], &0xdead_beefu32.to_le_bytes());
+        }
+
+        #[test]
+        fn tokenize_to_buffer_reports_buffer_too_small() {
+            let mut buf = [0u8; 3];
+            assert_eq!(
+                tokenize_to_buffer_no_args(&mut buf, 1),
+                Err(Error::BufferTooSmall)
+            );
+        }
+
+        #[test]
+        fn tokenize_to_writer_writes_token_then_args() {
+            WRITTEN.with(|written| written.borrow_mut().clear());
+
+            tokenize_to_writer::<RecordingWriter>(0x1234_5678, &[Argument::Varint(1)]).unwrap();
+
+            WRITTEN.with(|written| {
+                let written = written.borrow();
+                assert_eq!(&written[..4], &0x1234_5678u32.to_le_bytes());
+                assert_eq!(&written[4..], &[0x02]);
+            });
+        }
+    }
+}
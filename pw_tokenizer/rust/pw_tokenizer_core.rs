@@ -22,6 +22,14 @@
 
 pub const HASH_CONSTANT: u32 = 65599u32;
 
+/// The default number of bytes of a string that are hashed, matching the
+/// C/C++ tokenizer's default `PW_TOKENIZER_CFG_C_HASH_LENGTH`. Hashing only
+/// a string's first [`DEFAULT_HASH_LENGTH`] bytes keeps a token stable when
+/// text is appended past that point, and keeps tokens generated by the
+/// Rust, C, and C++ tokenizers compatible for strings that share a token
+/// database.
+pub const DEFAULT_HASH_LENGTH: usize = 128;
+
 /// Calculate the hash for a sequence of bytes.
 ///
 /// ```
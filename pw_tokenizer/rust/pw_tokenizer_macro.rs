@@ -26,15 +26,68 @@ use syn::{
 };
 
 use pw_format::macros::{generate_printf, FormatAndArgs, PrintfFormatMacroGenerator, Result};
-use pw_tokenizer_core::{hash_string, TOKENIZER_ENTRY_MAGIC};
+use pw_tokenizer_core::{hash_bytes_fixed, hash_string, TOKENIZER_ENTRY_MAGIC};
 
 type TokenStream2 = proc_macro2::TokenStream;
 
+mod kw {
+    syn::custom_keyword!(domain);
+}
+
+// Parses an optional leading `domain: "some_domain",` clause that may
+// precede the rest of a macro's arguments. Returns the parsed domain, or
+// the default domain (`""`) if the clause is absent.
+fn parse_optional_domain(input: ParseStream) -> syn::parse::Result<String> {
+    if input.peek(kw::domain) && input.peek2(Token![:]) {
+        input.parse::<kw::domain>()?;
+        input.parse::<Token![:]>()?;
+        let domain: LitStr = input.parse()?;
+        input.parse::<Token![,]>()?;
+        Ok(domain.value())
+    } else {
+        Ok(String::new())
+    }
+}
+
+// Returns true if `ty` is a 64-bit integer width. These need to go out over
+// the wire as a full 64-bit varint (`Argument::Varint64`) rather than being
+// truncated to the 32-bit `Argument::Varint`.
+fn is_64_bit_width(ty: &Ident) -> bool {
+    matches!(ty.to_string().as_str(), "i64" | "u64" | "isize" | "usize")
+}
+
+// Returns the number of bytes of a format string that should be hashed to
+// compute its token, matching the C/C++ tokenizer's configurable
+// `PW_TOKENIZER_CFG_C_HASH_LENGTH`: overridable at build time by setting the
+// `PW_TOKENIZER_CFG_C_HASH_LENGTH` environment variable, and falling back to
+// `pw_tokenizer_core::DEFAULT_HASH_LENGTH` (128, the C/C++ default) if unset
+// or unparsable. A value of `0` hashes the entire string, with no limit.
+fn hash_length() -> usize {
+    std::env::var("PW_TOKENIZER_CFG_C_HASH_LENGTH")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(pw_tokenizer_core::DEFAULT_HASH_LENGTH)
+}
+
+// Hashes `string` to compute its token.
+//
+// Only the first `hash_length()` bytes of `string` are hashed, matching the
+// C/C++ tokenizer's default `PW_TOKENIZER_CFG_C_HASH_LENGTH`. This keeps a
+// token stable when text is appended past that point and keeps tokens
+// compatible across the Rust, C, and C++ tokenizers for strings that share
+// a token database.
+fn token_hash(string: &str) -> u32 {
+    match hash_length() {
+        0 => hash_string(string),
+        len => hash_bytes_fixed(string.as_bytes(), len),
+    }
+}
+
 // Handles tokenizing (hashing) `string` and adding it to the token database
 // with the specified `domain`.  A detailed description of what's happening is
 // found in the docs for [`pw_tokenizer::token`] macro.
 fn token_backend(domain: &str, string: &str) -> TokenStream2 {
-    let hash = hash_string(string);
+    let hash = token_hash(string);
 
     // Line number is omitted as getting that info requires an experimental API:
     // https://doc.rust-lang.org/proc_macro/struct.Span.html#method.start
@@ -95,21 +148,50 @@ pub fn _token(tokens: TokenStream) -> TokenStream {
     token_backend("", &input.value()).into()
 }
 
+// Args to `token_with_domain!` that are parsed according to the pattern:
+//   ($domain:literal, $string:literal)
+#[derive(Debug)]
+struct TokenWithDomainArgs {
+    domain: LitStr,
+    string: LitStr,
+}
+
+impl Parse for TokenWithDomainArgs {
+    fn parse(input: ParseStream) -> syn::parse::Result<Self> {
+        let domain: LitStr = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let string: LitStr = input.parse()?;
+
+        Ok(Self { domain, string })
+    }
+}
+
+// Exported as `pw_tokenizer::token_with_domain`. Like `_token`, but adds the
+// string to the token database under `domain` instead of the default domain.
+#[proc_macro]
+pub fn _token_with_domain(tokens: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(tokens as TokenWithDomainArgs);
+    token_backend(&input.domain.value(), &input.string.value()).into()
+}
+
 // Args to tokenize to buffer that are parsed according to the pattern:
-//   ($buffer:expr, $format_string:literal, $($args:expr),*)
+//   ([domain: $domain:literal,] $buffer:expr, $format_string:literal, $($args:expr),*)
 #[derive(Debug)]
 struct TokenizeToBufferArgs {
+    domain: String,
     buffer: Expr,
     format_and_args: FormatAndArgs,
 }
 
 impl Parse for TokenizeToBufferArgs {
     fn parse(input: ParseStream) -> syn::parse::Result<Self> {
+        let domain = parse_optional_domain(input)?;
         let buffer: Expr = input.parse()?;
         input.parse::<Token![,]>()?;
         let format_and_args: FormatAndArgs = input.parse()?;
 
         Ok(TokenizeToBufferArgs {
+            domain,
             buffer,
             format_and_args,
         })
@@ -170,8 +252,14 @@ impl<'a> PrintfFormatMacroGenerator for TokenizeToBufferGenerator<'a> {
     }
 
     fn integer_conversion(&mut self, ty: Ident, expression: Expr) -> Result<Option<String>> {
-        self.encoding_fragments.push(quote! {
-          Argument::Varint(#ty::from(#expression) as i32)
+        self.encoding_fragments.push(if is_64_bit_width(&ty) {
+            quote! {
+              Argument::Varint64(#ty::from(#expression) as i64)
+            }
+        } else {
+            quote! {
+              Argument::Varint(#ty::from(#expression) as i32)
+            }
         });
 
         Ok(None)
@@ -190,6 +278,20 @@ impl<'a> PrintfFormatMacroGenerator for TokenizeToBufferGenerator<'a> {
         });
         Ok(None)
     }
+
+    fn float_conversion(&mut self, ty: Ident, expression: Expr) -> Result<Option<String>> {
+        self.encoding_fragments.push(if ty == "f32" {
+            quote! {
+              Argument::Float(#ty::from(#expression))
+            }
+        } else {
+            quote! {
+              Argument::Double(#ty::from(#expression))
+            }
+        });
+
+        Ok(None)
+    }
 }
 
 // Generates code to marshal a tokenized string and arguments into a buffer.
@@ -201,8 +303,7 @@ impl<'a> PrintfFormatMacroGenerator for TokenizeToBufferGenerator<'a> {
 pub fn _tokenize_to_buffer(tokens: TokenStream) -> TokenStream {
     let input = parse_macro_input!(tokens as TokenizeToBufferArgs);
 
-    // Hard codes domain to "".
-    let generator = TokenizeToBufferGenerator::new("", &input.buffer);
+    let generator = TokenizeToBufferGenerator::new(&input.domain, &input.buffer);
 
     match generate_printf(generator, input.format_and_args) {
         Ok(token_stream) => token_stream.into(),
@@ -211,20 +312,23 @@ pub fn _tokenize_to_buffer(tokens: TokenStream) -> TokenStream {
 }
 
 // Args to tokenize to buffer that are parsed according to the pattern:
-//   ($ty:ty, $format_string:literal, $($args:expr),*)
+//   ([domain: $domain:literal,] $ty:ty, $format_string:literal, $($args:expr),*)
 #[derive(Debug)]
 struct TokenizeToWriterArgs {
+    domain: String,
     ty: Type,
     format_and_args: FormatAndArgs,
 }
 
 impl Parse for TokenizeToWriterArgs {
     fn parse(input: ParseStream) -> syn::parse::Result<Self> {
+        let domain = parse_optional_domain(input)?;
         let ty: Type = input.parse()?;
         input.parse::<Token![,]>()?;
         let format_and_args: FormatAndArgs = input.parse()?;
 
         Ok(Self {
+            domain,
             ty,
             format_and_args,
         })
@@ -284,8 +388,14 @@ impl<'a> PrintfFormatMacroGenerator for TokenizeToWriterGenerator<'a> {
     }
 
     fn integer_conversion(&mut self, ty: Ident, expression: Expr) -> Result<Option<String>> {
-        self.encoding_fragments.push(quote! {
-          Argument::Varint(#ty::from(#expression) as i32)
+        self.encoding_fragments.push(if is_64_bit_width(&ty) {
+            quote! {
+              Argument::Varint64(#ty::from(#expression) as i64)
+            }
+        } else {
+            quote! {
+              Argument::Varint(#ty::from(#expression) as i32)
+            }
         });
 
         Ok(None)
@@ -304,14 +414,27 @@ impl<'a> PrintfFormatMacroGenerator for TokenizeToWriterGenerator<'a> {
         });
         Ok(None)
     }
+
+    fn float_conversion(&mut self, ty: Ident, expression: Expr) -> Result<Option<String>> {
+        self.encoding_fragments.push(if ty == "f32" {
+            quote! {
+              Argument::Float(#ty::from(#expression))
+            }
+        } else {
+            quote! {
+              Argument::Double(#ty::from(#expression))
+            }
+        });
+
+        Ok(None)
+    }
 }
 
 #[proc_macro]
 pub fn _tokenize_to_writer(tokens: TokenStream) -> TokenStream {
     let input = parse_macro_input!(tokens as TokenizeToWriterArgs);
 
-    // Hard codes domain to "".
-    let generator = TokenizeToWriterGenerator::new("", &input.ty);
+    let generator = TokenizeToWriterGenerator::new(&input.domain, &input.ty);
 
     match generate_printf(generator, input.format_and_args) {
         Ok(token_stream) => token_stream.into(),
@@ -0,0 +1,428 @@
+// Copyright 2023 The Pigweed Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! # pw_tokenizer_tokendb
+//!
+//! Build-time extraction of a token database from a compiled ELF (or Mach-O)
+//! binary.
+//!
+//! Every tokenized string produced by `pw_tokenizer_macro` is emitted as a
+//! `TokenEntry` record into a `.pw_tokenizer.entries.*` section (or a `,pw,`
+//! section on macOS, where Mach-O section names are limited to 16
+//! characters). This crate walks those sections in a built binary, decodes
+//! each record, and assembles a deduplicated database keyed by
+//! `(domain, token)` that can be used offline to detokenize logs.
+//!
+//! Because [`pw_tokenizer_core::hash_string`] is a 32-bit non-perfect hash,
+//! it is possible (if unlikely) for two distinct strings in the same domain
+//! to hash to the same token. Such a collision would silently corrupt
+//! detokenization, so [`extract_token_database`] treats it as an error
+//! rather than arbitrarily picking one of the colliding strings.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use object::{Object, ObjectSection};
+
+use pw_tokenizer_core::TOKENIZER_ENTRY_MAGIC;
+
+/// A single decoded token database entry.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TokenDatabaseEntry {
+    pub domain: String,
+    pub token: u32,
+    pub string: String,
+}
+
+/// Errors that can occur while extracting a token database.
+#[derive(Debug)]
+pub enum Error {
+    /// `elf_data` could not be parsed as an object file.
+    Object(object::Error),
+
+    /// A `TokenEntry` record's magic number didn't match
+    /// [`TOKENIZER_ENTRY_MAGIC`], indicating a corrupt or misaligned
+    /// section.
+    BadMagic { section: String, offset: usize },
+
+    /// A `TokenEntry` record ran past the end of its section.
+    Truncated { section: String, offset: usize },
+
+    /// Two distinct strings hashed to the same token within the same
+    /// domain. This is fatal because the token database can no longer
+    /// unambiguously map the token back to a string.
+    Collision {
+        domain: String,
+        token: u32,
+        first: String,
+        second: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Object(e) => write!(f, "failed to parse object file: {e}"),
+            Self::BadMagic { section, offset } => write!(
+                f,
+                "bad token entry magic in section {section} at offset {offset:#x}"
+            ),
+            Self::Truncated { section, offset } => write!(
+                f,
+                "truncated token entry in section {section} at offset {offset:#x}"
+            ),
+            Self::Collision {
+                domain,
+                token,
+                first,
+                second,
+            } => write!(
+                f,
+                "token collision in domain {domain:?}: token {token:#010x} maps to \
+                 both {first:?} and {second:?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Extracts a deduplicated, sorted token database from the
+/// `.pw_tokenizer.entries.*` sections of `elf_data`.
+///
+/// Returns [`Error::Collision`] if two distinct strings hash to the same
+/// token within the same domain.
+pub fn extract_token_database(elf_data: &[u8]) -> Result<Vec<TokenDatabaseEntry>, Error> {
+    let file = object::File::parse(elf_data).map_err(Error::Object)?;
+    let mut database: BTreeMap<(String, u32), String> = BTreeMap::new();
+
+    for section in file.sections() {
+        let name = section.name().unwrap_or("");
+        if !is_token_entries_section(name) {
+            continue;
+        }
+        let data = section.data().map_err(Error::Object)?;
+        decode_section_entries(name, data, &mut database)?;
+    }
+
+    Ok(database
+        .into_iter()
+        .map(|((domain, token), string)| TokenDatabaseEntry {
+            domain,
+            token,
+            string,
+        })
+        .collect())
+}
+
+// A section holds token entries if it is named `.pw_tokenizer.entries.*`
+// (the normal ELF case) or is exactly `,pw,` (the Mach-O case). See
+// `token_backend` in `pw_tokenizer_macro` for where these names are
+// emitted.
+fn is_token_entries_section(name: &str) -> bool {
+    name.starts_with(".pw_tokenizer.entries.") || name == ",pw,"
+}
+
+// Decodes every `TokenEntry` record packed into a single section's raw
+// bytes, inserting each into `database` and erroring on a token collision.
+fn decode_section_entries(
+    section: &str,
+    data: &[u8],
+    database: &mut BTreeMap<(String, u32), String>,
+) -> Result<(), Error> {
+    let mut offset = 0;
+    while offset < data.len() {
+        let (entry, consumed) = parse_token_entry(section, data, offset)?;
+        offset += consumed;
+
+        let key = (entry.domain, entry.token);
+        match database.get(&key) {
+            Some(existing) if *existing != entry.string => {
+                return Err(Error::Collision {
+                    domain: key.0,
+                    token: key.1,
+                    first: existing.clone(),
+                    second: entry.string,
+                });
+            }
+            Some(_) => continue,
+            None => {
+                database.insert(key, entry.string);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Layout of `TokenEntry` as emitted by `token_backend`:
+//   magic: u32, token: u32, domain_size: u32, string_length: u32,
+//   domain: [u8; domain_size], string: [u8; string_length]
+const TOKEN_ENTRY_HEADER_LEN: usize = 16;
+
+fn parse_token_entry(
+    section: &str,
+    data: &[u8],
+    offset: usize,
+) -> Result<(TokenDatabaseEntry, usize), Error> {
+    let truncated = || Error::Truncated {
+        section: section.to_string(),
+        offset,
+    };
+
+    let header = data
+        .get(offset..offset + TOKEN_ENTRY_HEADER_LEN)
+        .ok_or_else(truncated)?;
+
+    let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    if magic != TOKENIZER_ENTRY_MAGIC {
+        return Err(Error::BadMagic {
+            section: section.to_string(),
+            offset,
+        });
+    }
+    let token = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let domain_size = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+    let string_length = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+
+    let domain_start = offset + TOKEN_ENTRY_HEADER_LEN;
+    let string_start = domain_start + domain_size;
+    let entry_end = string_start + string_length;
+
+    let domain_bytes = data.get(domain_start..string_start).ok_or_else(truncated)?;
+    let string_bytes = data.get(string_start..entry_end).ok_or_else(truncated)?;
+
+    Ok((
+        TokenDatabaseEntry {
+            domain: c_str_to_string(domain_bytes),
+            token,
+            string: c_str_to_string(string_bytes),
+        },
+        entry_end - offset,
+    ))
+}
+
+// `domain` and `string` are emitted as NUL-terminated C strings; strip the
+// trailing NUL before converting to a `String`.
+fn c_str_to_string(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// Serializes a token database using pigweed's canonical CSV schema, one
+/// `token,date_removed,string` row per line. `date_removed` is always left
+/// blank, since this tool has no concept of removing a token from the
+/// database. Entries are sorted by `(domain, token)` so that the output is
+/// stable across runs regardless of link order. Domains are not a CSV
+/// column: a `# domain: <name>` comment line precedes each domain's rows,
+/// including the default (empty) domain's, which is introduced by
+/// `# domain: ` with an empty name.
+pub fn write_csv<W: std::io::Write>(
+    database: &[TokenDatabaseEntry],
+    mut writer: W,
+) -> std::io::Result<()> {
+    let mut entries: Vec<&TokenDatabaseEntry> = database.iter().collect();
+    entries.sort_by(|a, b| (&a.domain, a.token).cmp(&(&b.domain, b.token)));
+
+    let mut current_domain: Option<&str> = None;
+    for entry in entries {
+        if current_domain != Some(entry.domain.as_str()) {
+            writeln!(writer, "# domain: {}", entry.domain)?;
+            current_domain = Some(entry.domain.as_str());
+        }
+        writeln!(
+            writer,
+            "{:08x},,{}",
+            entry.token,
+            escape_csv_field(&entry.string)
+        )?;
+    }
+    Ok(())
+}
+
+fn escape_csv_field(field: &str) -> String {
+    if field.contains(',') || field.contains('"') || field.contains('\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_entry(buf: &mut Vec<u8>, magic: u32, token: u32, domain: &str, string: &str) {
+        let domain = format!("{domain}\0");
+        let string = format!("{string}\0");
+        buf.extend_from_slice(&magic.to_le_bytes());
+        buf.extend_from_slice(&token.to_le_bytes());
+        buf.extend_from_slice(&(domain.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(string.len() as u32).to_le_bytes());
+        buf.extend_from_slice(domain.as_bytes());
+        buf.extend_from_slice(string.as_bytes());
+    }
+
+    #[test]
+    fn decodes_single_entry() {
+        let mut data = Vec::new();
+        push_entry(&mut data, TOKENIZER_ENTRY_MAGIC, 0x1234, "", "hello");
+
+        let mut database = BTreeMap::new();
+        decode_section_entries(".pw_tokenizer.entries.00001234", &data, &mut database).unwrap();
+
+        assert_eq!(
+            database.get(&("".to_string(), 0x1234)),
+            Some(&"hello".to_string())
+        );
+    }
+
+    #[test]
+    fn decodes_multiple_entries_in_one_section() {
+        let mut data = Vec::new();
+        push_entry(&mut data, TOKENIZER_ENTRY_MAGIC, 1, "logs", "a");
+        push_entry(&mut data, TOKENIZER_ENTRY_MAGIC, 2, "logs", "b");
+
+        let mut database = BTreeMap::new();
+        decode_section_entries(",pw,", &data, &mut database).unwrap();
+
+        assert_eq!(database.len(), 2);
+        assert_eq!(database[&("logs".to_string(), 1)], "a");
+        assert_eq!(database[&("logs".to_string(), 2)], "b");
+    }
+
+    #[test]
+    fn duplicate_identical_entries_are_deduplicated() {
+        let mut data = Vec::new();
+        push_entry(&mut data, TOKENIZER_ENTRY_MAGIC, 1, "", "same");
+        push_entry(&mut data, TOKENIZER_ENTRY_MAGIC, 1, "", "same");
+
+        let mut database = BTreeMap::new();
+        decode_section_entries(".pw_tokenizer.entries.00000001", &data, &mut database).unwrap();
+
+        assert_eq!(database.len(), 1);
+    }
+
+    #[test]
+    fn colliding_entries_are_rejected() {
+        let mut data = Vec::new();
+        push_entry(&mut data, TOKENIZER_ENTRY_MAGIC, 1, "", "first");
+        push_entry(&mut data, TOKENIZER_ENTRY_MAGIC, 1, "", "second");
+
+        let mut database = BTreeMap::new();
+        let err =
+            decode_section_entries(".pw_tokenizer.entries.00000001", &data, &mut database)
+                .unwrap_err();
+
+        assert!(matches!(err, Error::Collision { token: 1, .. }));
+    }
+
+    #[test]
+    fn same_token_in_different_domains_does_not_collide() {
+        let mut data = Vec::new();
+        push_entry(&mut data, TOKENIZER_ENTRY_MAGIC, 1, "logs", "first");
+        push_entry(&mut data, TOKENIZER_ENTRY_MAGIC, 1, "asserts", "second");
+
+        let mut database = BTreeMap::new();
+        decode_section_entries(".pw_tokenizer.entries.00000001", &data, &mut database).unwrap();
+
+        assert_eq!(database.len(), 2);
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let mut data = Vec::new();
+        push_entry(&mut data, 0xdeadbeef, 1, "", "hello");
+
+        let mut database = BTreeMap::new();
+        let err =
+            decode_section_entries(".pw_tokenizer.entries.00000001", &data, &mut database)
+                .unwrap_err();
+
+        assert!(matches!(err, Error::BadMagic { .. }));
+    }
+
+    #[test]
+    fn truncated_entry_is_rejected() {
+        let mut data = Vec::new();
+        push_entry(&mut data, TOKENIZER_ENTRY_MAGIC, 1, "", "hello");
+        data.truncate(data.len() - 1);
+
+        let mut database = BTreeMap::new();
+        let err =
+            decode_section_entries(".pw_tokenizer.entries.00000001", &data, &mut database)
+                .unwrap_err();
+
+        assert!(matches!(err, Error::Truncated { .. }));
+    }
+
+    #[test]
+    fn recognizes_token_entry_sections() {
+        assert!(is_token_entries_section(".pw_tokenizer.entries.0badf00d"));
+        assert!(is_token_entries_section(",pw,"));
+        assert!(!is_token_entries_section(".text"));
+        assert!(!is_token_entries_section(".pw_tokenizer.info"));
+    }
+
+    #[test]
+    fn csv_output_is_sorted_and_escaped() {
+        let database = vec![
+            TokenDatabaseEntry {
+                domain: "".to_string(),
+                token: 2,
+                string: "needs, escaping".to_string(),
+            },
+            TokenDatabaseEntry {
+                domain: "".to_string(),
+                token: 1,
+                string: "plain".to_string(),
+            },
+        ];
+
+        let mut out = Vec::new();
+        write_csv(&database, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(
+            out,
+            "# domain: \n00000001,,plain\n00000002,,\"needs, escaping\"\n"
+        );
+    }
+
+    #[test]
+    fn csv_output_groups_rows_under_a_domain_comment() {
+        let database = vec![
+            TokenDatabaseEntry {
+                domain: "asserts".to_string(),
+                token: 2,
+                string: "assert string".to_string(),
+            },
+            TokenDatabaseEntry {
+                domain: "logs".to_string(),
+                token: 1,
+                string: "log string".to_string(),
+            },
+        ];
+
+        let mut out = Vec::new();
+        write_csv(&database, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(
+            out,
+            "# domain: asserts\n00000002,,assert string\n\
+             # domain: logs\n00000001,,log string\n"
+        );
+    }
+}